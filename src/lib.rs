@@ -1,113 +1,553 @@
+use std::fmt;
 use std::io;
-use std::io::{Error, ErrorKind};
-use std::collections::BTreeSet;
-use crate::SexprTree::{Sym, Sub};
+use std::collections::{BTreeSet, HashMap};
+use crate::SexprTree::{Atom, Sub};
 
-pub fn errorize<T>(msg: String) -> io::Result<T> {
-    Err(Error::new(ErrorKind::Other, msg.as_str()))
+/// A half-open byte range plus the 1-indexed line/column of its first character.
+///
+/// `line`/`col` refer to the position of the byte at `start`; `end` is exclusive,
+/// matching the usual slice convention (`&src[start..end]`).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    fn eof(start: usize, line: usize, col: usize) -> Self {
+        Span {start, end: start, line, col}
+    }
+}
+
+/// A single lexeme, already classified by kind so consumers can match on it
+/// instead of re-parsing the underlying string. `Open`/`Close` carry the actual
+/// bracket character, since `ParserConfig` can configure more than one bracket kind.
+/// `Int`/`Float` carry the parsed value alongside the original source text, since
+/// reformatting a number from its parsed value (`"3.0"` -> `3`, `"1e5"` -> `100000`)
+/// would silently rewrite the lexeme.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Open(char),
+    Close(char),
+    Atom(String),
+    Str(String),
+    Int(i64, String),
+    Float(f64, String),
+}
+
+impl Token {
+    /// The textual form of this token: the bracket character, the atom text, the raw
+    /// source text of a number, or the decoded contents of a `Str` (without its quotes
+    /// and with escapes already resolved).
+    pub fn text(&self) -> String {
+        match self {
+            Token::Open(c) => c.to_string(),
+            Token::Close(c) => c.to_string(),
+            Token::Atom(s) => s.clone(),
+            Token::Str(s) => s.clone(),
+            Token::Int(_, raw) => raw.clone(),
+            Token::Float(_, raw) => raw.clone(),
+        }
+    }
+}
+
+/// The structured error a `Parser` can report, in place of a stringly-typed `io::Error`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken {expected: String, found: String, span: Span},
+    UnmatchedOpen {span: Span},
+    UnexpectedClose {span: Span},
+    UnterminatedString {span: Span},
+    InvalidEscape {span: Span},
+    UnexpectedEof,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken {expected, found, span} =>
+                write!(f, "expected {}, found '{}' at {}:{}", expected, found, span.line, span.col),
+            ParseError::UnmatchedOpen {span} =>
+                write!(f, "unmatched opening bracket at {}:{}", span.line, span.col),
+            ParseError::UnexpectedClose {span} =>
+                write!(f, "unexpected closing bracket at {}:{}", span.line, span.col),
+            ParseError::UnterminatedString {span} =>
+                write!(f, "unterminated string starting at {}:{}", span.line, span.col),
+            ParseError::InvalidEscape {span} =>
+                write!(f, "invalid \\u escape at {}:{}", span.line, span.col),
+            ParseError::UnexpectedEof =>
+                write!(f, "unexpected end of input"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Configures the grammar a `Parser` lexes and parses against: which bracket pairs
+/// delimit a `Sub`, what (if any) prefix starts a line comment, and whether bare
+/// atoms are case-folded. The default grammar is plain Lisp: `()`, `;` comments,
+/// case preserved.
+#[derive(Debug, Clone)]
+pub struct ParserConfig {
+    brackets: Vec<(char, char)>,
+    comment_prefix: Option<char>,
+    lowercase: bool,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {brackets: vec![('(', ')')], comment_prefix: Some(';'), lowercase: false}
+    }
+}
+
+impl ParserConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `open`/`close` as another balanced bracket kind (e.g. `[`/`]` or `{`/`}`),
+    /// alongside whatever this config already recognizes.
+    pub fn with_brackets(mut self, open: char, close: char) -> Self {
+        self.brackets.push((open, close));
+        self
+    }
+
+    /// Sets the line-comment prefix; text from this character to end-of-line is skipped.
+    pub fn with_comment_prefix(mut self, prefix: char) -> Self {
+        self.comment_prefix = Some(prefix);
+        self
+    }
+
+    pub fn without_comments(mut self) -> Self {
+        self.comment_prefix = None;
+        self
+    }
+
+    pub fn with_lowercasing(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Every character this grammar reserves for structure rather than atom text:
+    /// each configured bracket (open and close) plus the line-comment prefix, if any.
+    /// An atom serializer needs these to know what must be quoted to round-trip.
+    fn reserved_chars(&self) -> BTreeSet<char> {
+        let mut reserved: BTreeSet<char> = self.brackets.iter()
+            .flat_map(|(open, close)| [*open, *close])
+            .collect();
+        reserved.extend(self.comment_prefix);
+        reserved
+    }
 }
 
 struct Tokenizer {
     pending: String,
-    symbols: BTreeSet<char>
+    pending_start: Option<(usize, usize, usize)>,
+    opens: BTreeSet<char>,
+    closes: BTreeSet<char>,
+    comment_prefix: Option<char>,
+    lowercase: bool
 }
 
 impl Tokenizer {
-    pub fn new(symbols: &str) -> Self {
-        let mut result = Tokenizer {pending: String::new(), symbols: BTreeSet::new()};
-        symbols.chars().for_each(|c| {result.symbols.insert(c);});
-        result
+    fn new(config: &ParserConfig) -> Self {
+        let mut opens = BTreeSet::new();
+        let mut closes = BTreeSet::new();
+        for (open, close) in &config.brackets {
+            opens.insert(*open);
+            closes.insert(*close);
+        }
+        Tokenizer {pending: String::new(), pending_start: None, opens, closes, comment_prefix: config.comment_prefix, lowercase: config.lowercase}
     }
 
-    pub fn tokenize(&mut self, text: &str) -> Vec<String> {
+    fn tokenize(&mut self, text: &str) -> Result<Vec<(Token, Span)>, ParseError> {
         let mut tokens = Vec::new();
-        text.chars().for_each(|c| {
-            if self.symbols.contains(&c) {
-                self.add_pending(&mut tokens);
-                let mut cstr = String::new();
-                cstr.push(c);
-                tokens.push(cstr);
+        let mut chars = text.chars().peekable();
+        let mut offset = 0;
+        let mut line = 1;
+        let mut col = 1;
+        while let Some(c) = chars.next() {
+            if c == '"' {
+                let start = Span::eof(offset, line, col);
+                offset += c.len_utf8();
+                col += 1;
+                fn advance(c: char, offset: &mut usize, line: &mut usize, col: &mut usize) {
+                    *offset += c.len_utf8();
+                    if c == '\n' {
+                        *line += 1;
+                        *col = 1;
+                    } else {
+                        *col += 1;
+                    }
+                }
+                let mut contents = String::new();
+                let mut closed = false;
+                while let Some(&next) = chars.peek() {
+                    chars.next();
+                    if next == '"' {
+                        advance(next, &mut offset, &mut line, &mut col);
+                        closed = true;
+                        break;
+                    } else if next == '\\' {
+                        let escape_start = Span::eof(offset, line, col);
+                        advance(next, &mut offset, &mut line, &mut col);
+                        match chars.next() {
+                            Some('"') => {contents.push('"'); advance('"', &mut offset, &mut line, &mut col);}
+                            Some('\\') => {contents.push('\\'); advance('\\', &mut offset, &mut line, &mut col);}
+                            Some('n') => {contents.push('\n'); advance('n', &mut offset, &mut line, &mut col);}
+                            Some('t') => {contents.push('\t'); advance('t', &mut offset, &mut line, &mut col);}
+                            Some('u') => {
+                                advance('u', &mut offset, &mut line, &mut col);
+                                let mut hex = String::new();
+                                for _ in 0..4 {
+                                    match chars.next() {
+                                        Some(h) => {hex.push(h); advance(h, &mut offset, &mut line, &mut col);}
+                                        None => return Err(ParseError::UnterminatedString {span: start})
+                                    }
+                                }
+                                let decoded = u32::from_str_radix(&hex, 16).ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or(ParseError::InvalidEscape {span: escape_start})?;
+                                contents.push(decoded);
+                            }
+                            Some(other) => {contents.push(other); advance(other, &mut offset, &mut line, &mut col);}
+                            None => return Err(ParseError::UnterminatedString {span: start})
+                        }
+                    } else {
+                        advance(next, &mut offset, &mut line, &mut col);
+                        contents.push(next);
+                    }
+                }
+                if !closed {
+                    return Err(ParseError::UnterminatedString {span: start});
+                }
+                tokens.push((Token::Str(contents), Span {start: start.start, end: offset, line: start.line, col: start.col}));
+            } else if Some(c) == self.comment_prefix {
+                self.add_pending(&mut tokens, offset);
+                offset += c.len_utf8();
+                col += 1;
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                    offset += next.len_utf8();
+                    col += 1;
+                }
+            } else if self.opens.contains(&c) || self.closes.contains(&c) {
+                self.add_pending(&mut tokens, offset);
+                let token = if self.opens.contains(&c) {Token::Open(c)} else {Token::Close(c)};
+                tokens.push((token, Span {start: offset, end: offset + c.len_utf8(), line, col}));
+                offset += c.len_utf8();
+                col += 1;
             } else if c.is_whitespace() {
-                self.add_pending(&mut tokens);
+                self.add_pending(&mut tokens, offset);
+                offset += c.len_utf8();
+                if c == '\n' {
+                    line += 1;
+                    col = 1;
+                } else {
+                    col += 1;
+                }
             } else {
+                if self.pending.is_empty() {
+                    self.pending_start = Some((offset, line, col));
+                }
                 self.pending.push(c);
+                offset += c.len_utf8();
+                col += 1;
             }
-        });
-        self.add_pending(&mut tokens);
-        tokens
+        }
+        self.add_pending(&mut tokens, offset);
+        Ok(tokens)
     }
 
-    fn add_pending(&mut self, tokens: &mut Vec<String>) {
+    fn add_pending(&mut self, tokens: &mut Vec<(Token, Span)>, end: usize) {
         if self.pending.len() > 0 {
-            tokens.push(self.pending.to_lowercase());
-            self.pending = String::new();
+            let (start, line, col) = self.pending_start.take().unwrap();
+            let raw = std::mem::take(&mut self.pending);
+            let text = if self.lowercase {raw.to_lowercase()} else {raw};
+            tokens.push((Self::atomize(text), Span {start, end, line, col}));
+        }
+    }
+
+    fn atomize(text: String) -> Token {
+        // `f64::parse` also accepts `nan`/`inf`/`infinity` with no digits at all, which
+        // would otherwise turn those bare symbols into numbers; require a digit first.
+        if !text.bytes().any(|b| b.is_ascii_digit()) {
+            return Token::Atom(text);
+        }
+        if let Ok(i) = text.parse::<i64>() {
+            Token::Int(i, text)
+        } else if let Ok(f) = text.parse::<f64>() {
+            Token::Float(f, text)
+        } else {
+            Token::Atom(text)
         }
     }
 }
 
-#[derive(Debug,Clone,Eq, PartialEq)]
-pub enum SexprTree {
-    Sym(String),
-    Sub(Vec<SexprTree>)
+/// A parsed s-expression, generic over the payload carried by each leaf.
+///
+/// `SexprTree<String>` (the default) is the plain tree of raw atom text, and
+/// deliberately collapses `Token::Atom`/`Str`/`Int`/`Float` down to their shared
+/// textual form: a quoted `"2"` and the bare number `2` both become `Atom("2".into())`.
+/// Callers that need the string-vs-symbol-vs-number distinction preserved on the leaf
+/// should parse into a richer `SexprTree<A>` via [`Parser::build_parse_tree_with`], whose
+/// `convert` closure sees the whole [`Token`] (so it can match on kind before the
+/// distinction is lost), or convert an existing tree with [`SexprTree::map`].
+#[derive(Debug, Clone)]
+pub enum SexprTree<A = String> {
+    Atom(A, Span),
+    Sub(Vec<SexprTree<A>>, Span)
+}
+
+/// Structural equality: two trees are equal if their atoms and shape match,
+/// regardless of where in the source each one was parsed from.
+impl<A: PartialEq> PartialEq for SexprTree<A> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Atom(a, _), Atom(b, _)) => a == b,
+            (Sub(a, _), Sub(b, _)) => a == b,
+            _ => false
+        }
+    }
 }
 
-impl SexprTree {
-    pub fn is(&self, target: &str) ->  bool {
+impl<A> SexprTree<A> {
+    pub fn span(&self) -> Span {
         match self {
-            Sub(_) => false,
-            Sym(s) => s == target
+            Atom(_, span) => *span,
+            Sub(_, span) => *span
         }
     }
 
-    pub fn head(&self) -> Option<String> {
+    /// The atom in the operator position of a list like `(+ 1 2)`, i.e. the
+    /// leading leaf of the leftmost spine of this (sub)tree.
+    pub fn head(&self) -> Option<&A> {
         match self {
-            Sym(s) => Some(s.clone()),
-            Sub(v) => v.get(0).and_then(|s| s.head())
+            Atom(a, _) => Some(a),
+            Sub(v, _) => v.get(0).and_then(|s| s.head())
         }
     }
 
-    pub fn flatten(&self) -> Vec<String> {
+    pub fn flatten(&self) -> Vec<&A> {
         let mut result = Vec::new();
         self.flatten_help(&mut result);
         result
     }
 
-    fn flatten_help(&self, flattened: &mut Vec<String>) {
+    fn flatten_help<'a>(&'a self, flattened: &mut Vec<&'a A>) {
         match self {
-            Sym(s) => flattened.push(s.clone()),
-            Sub(v) => v.iter().for_each(|s| s.flatten_help(flattened))
+            Atom(a, _) => flattened.push(a),
+            Sub(v, _) => v.iter().for_each(|s| s.flatten_help(flattened))
+        }
+    }
+
+    /// Transform every atom leaf with `f`, leaving the `Sub` structure (and spans) intact.
+    pub fn map<B>(self, f: &mut impl FnMut(A) -> B) -> SexprTree<B> {
+        match self {
+            Atom(a, span) => SexprTree::Atom(f(a), span),
+            Sub(v, span) => SexprTree::Sub(v.into_iter().map(|c| c.map(f)).collect(), span)
+        }
+    }
+
+    /// Like [`SexprTree::map`], but `f` can fail; the first error short-circuits the walk.
+    pub fn try_map<B, E>(self, f: &mut impl FnMut(A) -> Result<B, E>) -> Result<SexprTree<B>, E> {
+        match self {
+            Atom(a, span) => Ok(SexprTree::Atom(f(a)?, span)),
+            Sub(v, span) => {
+                let mapped = v.into_iter().map(|c| c.try_map(f)).collect::<Result<Vec<_>, E>>()?;
+                Ok(SexprTree::Sub(mapped, span))
+            }
+        }
+    }
+}
+
+impl<A: AsRef<str>> SexprTree<A> {
+    /// True if this leaf is an `Atom` whose text equals `target`; `Sub` nodes never match.
+    pub fn is(&self, target: &str) -> bool {
+        match self {
+            Atom(a, _) => a.as_ref() == target,
+            Sub(..) => false
+        }
+    }
+}
+
+/// Quotes `text` (re-escaping `"` and `\`) if it is empty or contains whitespace,
+/// a `"` or `\`, or any character `reserved` by the target grammar (its bracket
+/// chars and line-comment prefix); otherwise returns it unchanged. An atom
+/// containing, say, a bare `;` under the default grammar would otherwise
+/// re-lex as the start of a comment, so it must round-trip quoted. The inverse
+/// of the string-escape decoding `Tokenizer` performs while lexing.
+fn quote_if_needed(text: &str, reserved: &BTreeSet<char>) -> String {
+    let needs_quoting = text.is_empty()
+        || text.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\' || reserved.contains(&c));
+    if !needs_quoting {
+        return text.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            other => quoted.push(other)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl<A: fmt::Display> fmt::Display for SexprTree<A> {
+    /// Renders canonical, single-space-separated text under the default grammar: `(a b c)`.
+    /// Use [`SexprTree::to_string_with_config`] to round-trip against a non-default one.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_with_config(&ParserConfig::default()))
+    }
+}
+
+impl<A: fmt::Display> SexprTree<A> {
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{}", self)
+    }
+
+    /// Like [`SexprTree::write_to`], but quotes against `config`'s grammar instead of the
+    /// default one; see [`SexprTree::to_string_with_config`].
+    pub fn write_to_with_config<W: io::Write>(&self, w: &mut W, config: &ParserConfig) -> io::Result<()> {
+        write!(w, "{}", self.to_string_with_config(config))
+    }
+
+    /// Like [`Parser::build_parse_tree_with_config`]'s relationship to `build_parse_tree`:
+    /// renders canonical text quoted against `config`'s bracket chars and comment prefix
+    /// instead of the default grammar's, so the result round-trips under that config too.
+    pub fn to_string_with_config(&self, config: &ParserConfig) -> String {
+        let reserved = config.reserved_chars();
+        let mut out = String::new();
+        self.write_canonical(&mut out, &reserved);
+        out
+    }
+
+    fn write_canonical(&self, out: &mut String, reserved: &BTreeSet<char>) {
+        match self {
+            Atom(a, _) => out.push_str(&quote_if_needed(&a.to_string(), reserved)),
+            Sub(v, _) => {
+                out.push('(');
+                for (i, child) in v.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    child.write_canonical(out, reserved);
+                }
+                out.push(')');
+            }
+        }
+    }
+
+    /// Renders the tree with each nested `Sub` indented two spaces per level of depth,
+    /// for readable output of deep trees.
+    pub fn to_pretty(&self) -> String {
+        self.to_pretty_with_config(&ParserConfig::default())
+    }
+
+    /// Like [`SexprTree::to_pretty`], but quotes against `config`'s grammar instead of
+    /// the default one; see [`SexprTree::to_string_with_config`].
+    pub fn to_pretty_with_config(&self, config: &ParserConfig) -> String {
+        let reserved = config.reserved_chars();
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0, &reserved);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, depth: usize, reserved: &BTreeSet<char>) {
+        match self {
+            Atom(a, _) => out.push_str(&quote_if_needed(&a.to_string(), reserved)),
+            Sub(v, _) if v.is_empty() => out.push_str("()"),
+            Sub(v, _) => {
+                out.push('(');
+                for (i, child) in v.iter().enumerate() {
+                    if i > 0 {
+                        out.push('\n');
+                        out.push_str(&"  ".repeat(depth + 1));
+                    }
+                    child.write_pretty(out, depth + 1, reserved);
+                }
+                out.push(')');
+            }
         }
     }
 }
 
 pub struct Parser {
-    tokens: Vec<String>,
+    tokens: Vec<(Token, Span)>,
+    closers: HashMap<char, char>,
     i: usize
 }
 
 impl Parser {
-    pub fn new(src: &str) -> Self {
-        Parser {tokens: Tokenizer::new("()").tokenize(src), i: 0}
+    pub fn new(src: &str) -> Result<Self, ParseError> {
+        Self::with_config(src, &ParserConfig::default())
+    }
+
+    pub fn with_config(src: &str, config: &ParserConfig) -> Result<Self, ParseError> {
+        let tokens = Tokenizer::new(config).tokenize(src)?;
+        let closers = config.brackets.iter().cloned().collect();
+        Ok(Parser {tokens, closers, i: 0})
+    }
+
+    pub fn build_parse_tree(src: &str) -> Result<SexprTree<String>, ParseError> {
+        Self::build_parse_tree_with(src, |t| Ok(t.text()))
+    }
+
+    /// Like [`Parser::build_parse_tree`], but lexes and parses against a custom `ParserConfig`
+    /// instead of the default grammar.
+    pub fn build_parse_tree_with_config(src: &str, config: &ParserConfig) -> Result<SexprTree<String>, ParseError> {
+        let mut parser = Parser::with_config(src, config)?;
+        parser.tree_help(&mut |t| Ok(t.text()))
     }
 
-    pub fn build_parse_tree(src: &str) -> io::Result<SexprTree> {
-        let mut parser = Parser::new(src);
-        parser.tree_help()
+    /// Like [`Parser::build_parse_tree`], but each leaf's token is run through `convert`
+    /// to produce the tree's atom payload, letting callers parse directly into
+    /// `SexprTree<A>` instead of post-processing a `SexprTree<String>`. `convert` sees the
+    /// whole `Token`, not just its text, so it can match on kind (`Str` vs `Atom` vs a
+    /// already-lexed `Int`/`Float`) instead of re-parsing a string.
+    pub fn build_parse_tree_with<A>(src: &str, mut convert: impl FnMut(&Token) -> Result<A, ParseError>) -> Result<SexprTree<A>, ParseError> {
+        let mut parser = Parser::new(src)?;
+        parser.tree_help(&mut convert)
     }
 
-    fn tree_help(&mut self) -> io::Result<SexprTree> {
+    fn tree_help<A>(&mut self, convert: &mut impl FnMut(&Token) -> Result<A, ParseError>) -> Result<SexprTree<A>, ParseError> {
         if self.finished() {
-            Ok(SexprTree::Sub(vec![]))
-        } else if self.token()? == "(" {
-            let mut parts = Vec::new();
-            self.advance();
-            while !self.at_close()? {
-                parts.push(self.tree_help()?);
+            return Ok(SexprTree::Sub(vec![], Span::eof(0, 1, 1)));
+        }
+        match *self.token()? {
+            Token::Open(open) => {
+                let open_span = self.span()?;
+                let expected_close = *self.closers.get(&open).unwrap();
+                self.advance();
+                let mut parts = Vec::new();
+                loop {
+                    if self.finished() {
+                        return Err(ParseError::UnmatchedOpen {span: open_span});
+                    }
+                    if let Token::Close(close) = *self.token()? {
+                        let close_span = self.span()?;
+                        if close != expected_close {
+                            return Err(ParseError::UnexpectedClose {span: close_span});
+                        }
+                        self.advance();
+                        return Ok(SexprTree::Sub(parts, Span {start: open_span.start, end: close_span.end, line: open_span.line, col: open_span.col}));
+                    }
+                    parts.push(self.tree_help(convert)?);
+                }
+            }
+            Token::Close(_) => Err(ParseError::UnexpectedClose {span: self.span()?}),
+            _ => {
+                let span = self.span()?;
+                let token = self.snag()?;
+                Ok(SexprTree::Atom(convert(&token)?, span))
             }
-            self.advance();
-            Ok(SexprTree::Sub(parts))
-        } else {
-            Ok(SexprTree::Sym(self.snag()?))
         }
     }
 
@@ -115,25 +555,26 @@ impl Parser {
         self.i == self.tokens.len()
     }
 
-    pub fn token(&self) -> io::Result<&str> {
+    pub fn token(&self) -> Result<&Token, ParseError> {
         self.lookahead(0)
     }
 
-    pub fn lookahead(&self, distance: usize) -> io::Result<&str> {
-        let index = self.i + distance;
-        match self.tokens.get(index) {
-            Some(s) => Ok(s.as_str()),
-            None => errorize(format!("Token index '{}'; {} tokens available", index, self.tokens.len()))
-        }
+    pub fn span(&self) -> Result<Span, ParseError> {
+        self.tokens.get(self.i).map(|(_, span)| *span).ok_or(ParseError::UnexpectedEof)
+    }
+
+    pub fn lookahead(&self, distance: usize) -> Result<&Token, ParseError> {
+        self.tokens.get(self.i + distance).map(|(t, _)| t).ok_or(ParseError::UnexpectedEof)
     }
 
-    pub fn check(&mut self, target_token: &str) -> io::Result<()> {
+    pub fn check(&mut self, target_token: &Token) -> Result<(), ParseError> {
         let actual = self.token()?;
         if actual == target_token {
             self.advance();
             Ok(())
         } else {
-            errorize(format!("Token '{}' expected, token '{}' encountered at position {}", target_token, actual, self.i))
+            let (expected, found, span) = (target_token.text(), actual.text(), self.span()?);
+            Err(ParseError::UnexpectedToken {expected, found, span})
         }
     }
 
@@ -145,97 +586,464 @@ impl Parser {
         self.i += distance;
     }
 
-    pub fn at_close(&self) -> io::Result<bool> {
-        Ok(self.token()? == ")")
+    pub fn at_close(&self) -> Result<bool, ParseError> {
+        Ok(matches!(self.token()?, Token::Close(_)))
     }
 
-    pub fn snag_symbols(&mut self) -> io::Result<Vec<String>> {
-        self.check("(")?;
+    pub fn snag_symbols(&mut self) -> Result<Vec<String>, ParseError> {
+        if !matches!(self.token()?, Token::Open(_)) {
+            let (expected, found, span) = ("(".to_string(), self.token()?.text(), self.span()?);
+            return Err(ParseError::UnexpectedToken {expected, found, span});
+        }
+        self.advance();
         let mut result = Vec::new();
         while !self.at_close()? {
-            result.push(self.snag()?);
+            result.push(self.snag()?.text());
         }
-        self.check(")")?;
+        self.advance();
         Ok(result)
     }
 
-    pub fn snag(&mut self) -> io::Result<String> {
-        let token = self.token()?;
-        let result = String::from(token);
+    pub fn snag(&mut self) -> Result<Token, ParseError> {
+        let token = self.token()?.clone();
         self.advance();
-        Ok(result)
+        Ok(token)
+    }
+}
+
+/// Walks the children of a `Sub` node element-by-element, the way a macro expander
+/// walks a token tree, so a recursive-descent consumer doesn't have to index into
+/// the underlying `Vec` (and re-check its bounds) by hand.
+pub struct Cursor<'a, A> {
+    children: &'a [SexprTree<A>],
+    i: usize
+}
+
+impl<'a, A> Cursor<'a, A> {
+    /// A cursor over `tree`'s children, or `None` if `tree` is an `Atom` (which has none).
+    pub fn new(tree: &'a SexprTree<A>) -> Option<Self> {
+        match tree {
+            Sub(v, _) => Some(Cursor {children: v, i: 0}),
+            Atom(..) => None
+        }
+    }
+
+    pub fn peek(&self) -> Option<&'a SexprTree<A>> {
+        self.children.get(self.i)
+    }
+
+    /// Returns the current child and advances past it, or `None` once exhausted.
+    pub fn bump(&mut self) -> Option<&'a SexprTree<A>> {
+        let child = self.children.get(self.i)?;
+        self.i += 1;
+        Some(child)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.children.len() - self.i
+    }
+}
+
+impl<'a, A: AsRef<str>> Cursor<'a, A> {
+    /// Consumes the current child if it is an `Atom` equal to `target`, failing with
+    /// the child's span otherwise (or `UnexpectedEof` if there is no current child).
+    pub fn expect_sym(&mut self, target: &str) -> Result<&'a A, ParseError> {
+        match self.peek() {
+            Some(Atom(a, _)) if a.as_ref() == target => {
+                self.i += 1;
+                Ok(a)
+            }
+            Some(other) => Err(ParseError::UnexpectedToken {
+                expected: target.to_string(),
+                found: Self::describe(other),
+                span: other.span()
+            }),
+            None => Err(ParseError::UnexpectedEof)
+        }
+    }
+
+    /// Consumes the current child if it is a `Sub`, returning a cursor over its own
+    /// children; fails with the child's span if it is an `Atom` instead.
+    pub fn expect_sub(&mut self) -> Result<Cursor<'a, A>, ParseError> {
+        match self.peek() {
+            Some(node @ Sub(..)) => {
+                self.i += 1;
+                Ok(Cursor::new(node).unwrap())
+            }
+            Some(other) => Err(ParseError::UnexpectedToken {
+                expected: "a sub-expression".to_string(),
+                found: Self::describe(other),
+                span: other.span()
+            }),
+            None => Err(ParseError::UnexpectedEof)
+        }
+    }
+
+    fn describe(node: &SexprTree<A>) -> String {
+        match node {
+            Atom(a, _) => a.as_ref().to_string(),
+            Sub(..) => "(...)".to_string()
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Parser;
-    use std::io;
-    use crate::SexprTree::{Sym, Sub};
+    use crate::{Parser, ParserConfig, Token, ParseError, Cursor};
+    use crate::SexprTree::{Atom, Sub};
 
     const TEST_1: &str = "(+ (* 2 3) (- 5 4))";
 
     #[test]
     fn token_test() {
-        let tokens_1: Vec<&str> = vec!["(", "+", "(", "*", "2", "3", ")", "(", "-", "5", "4", ")", ")"];
+        let tokens_1: Vec<Token> = vec![
+            Token::Open('('), Token::Atom("+".to_string()), Token::Open('('), Token::Atom("*".to_string()),
+            Token::Int(2, "2".to_string()), Token::Int(3, "3".to_string()), Token::Close(')'), Token::Open('('), Token::Atom("-".to_string()),
+            Token::Int(5, "5".to_string()), Token::Int(4, "4".to_string()), Token::Close(')'), Token::Close(')')
+        ];
 
         snag_test(&tokens_1);
         check_test(&tokens_1);
         lookahead_test(&tokens_1);
     }
 
-    fn snag_test(tokens: &Vec<&str>) {
-        let mut p = Parser::new(TEST_1);
+    fn snag_test(tokens: &Vec<Token>) {
+        let mut p = Parser::new(TEST_1).unwrap();
         for token in tokens.iter() {
-            assert_eq!(*token, p.snag().unwrap().as_str());
+            assert_eq!(*token, p.snag().unwrap());
         }
         assert!(p.finished());
     }
 
-    fn check_test(tokens: &Vec<&str>) {
-        let mut p2 = Parser::new(TEST_1);
+    fn check_test(tokens: &Vec<Token>) {
+        let mut p2 = Parser::new(TEST_1).unwrap();
         for token in tokens.iter() {
-            p2.check(*token).unwrap();
+            p2.check(token).unwrap();
         }
         assert!(p2.finished());
     }
 
-    fn lookahead_test(tokens: &Vec<&str>) {
-        let mut p = Parser::new(TEST_1);
+    fn lookahead_test(tokens: &Vec<Token>) {
+        let mut p = Parser::new(TEST_1).unwrap();
         for i in 0..tokens.len() - 1 {
-            assert_eq!(tokens[i], p.token().unwrap());
-            assert_eq!(tokens[i+1], p.lookahead(1).unwrap());
+            assert_eq!(&tokens[i], p.token().unwrap());
+            assert_eq!(&tokens[i+1], p.lookahead(1).unwrap());
             p.advance();
         }
-        p.check(")").unwrap()
+        p.check(&Token::Close(')')).unwrap()
     }
 
     #[test]
     fn snag_symbols_test() {
-        let mut p = Parser::new(TEST_1);
-        p.check("(").unwrap();
-        p.check("+").unwrap();
+        let mut p = Parser::new(TEST_1).unwrap();
+        p.check(&Token::Open('(')).unwrap();
+        p.check(&Token::Atom("+".to_string())).unwrap();
         assert_eq!(p.snag_symbols().unwrap(), vec!["*", "2", "3"]);
         assert_eq!(p.snag_symbols().unwrap(), vec!["-", "5", "4"]);
         assert!(p.at_close().unwrap());
-        p.check(")").unwrap();
+        p.check(&Token::Close(')')).unwrap();
         assert!(p.finished());
     }
 
     #[test]
-    fn tree_test() -> io::Result<()> {
-        let tree = Parser::build_parse_tree(TEST_1)?;
+    fn tree_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
         match &tree {
-            Sym(_) => assert!(false),
-            Sub(v) => {
+            Atom(..) => assert!(false),
+            Sub(v, _) => {
                 assert!(v[0].is("+"));
                 assert_eq!(v[1].head().unwrap().as_str(), "*");
                 assert_eq!(v[2].head().unwrap().as_str(), "-");
             }
         }
-        assert_eq!(format!("{:?}", tree), r#"Sub([Sym("+"), Sub([Sym("*"), Sym("2"), Sym("3")]), Sub([Sym("-"), Sym("5"), Sym("4")])])"#);
         assert_eq!(tree.head().unwrap().as_str(), "+");
-        assert_eq!(tree.flatten(), vec!["+", "*", "2", "3", "-", "5", "4"]);
-        Ok(())
+        let flattened: Vec<&str> = tree.flatten().into_iter().map(|s| s.as_str()).collect();
+        assert_eq!(flattened, vec!["+", "*", "2", "3", "-", "5", "4"]);
+    }
+
+    #[test]
+    fn number_leaf_test() {
+        let tree = Parser::build_parse_tree("(+ 2 3.5)").unwrap();
+        match &tree {
+            Sub(v, _) => {
+                assert!(v[1].is("2"));
+                assert!(v[2].is("3.5"));
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn digitless_numeric_words_stay_atoms_test() {
+        let tree = Parser::build_parse_tree_with("(nan inf infinity)", |t| match t {
+            Token::Int(..) | Token::Float(..) => Err(ParseError::UnexpectedEof),
+            other => Ok(other.text()),
+        }).unwrap();
+        match &tree {
+            Sub(v, _) => {
+                assert!(v[0].is("nan"));
+                assert!(v[1].is("inf"));
+                assert!(v[2].is("infinity"));
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn build_parse_tree_with_test() {
+        let tree = Parser::build_parse_tree_with("(1 2 3.5)", |t| match t {
+            Token::Int(i, _) => Ok(*i as f64),
+            Token::Float(f, _) => Ok(*f),
+            _ => Err(ParseError::UnexpectedEof),
+        }).unwrap();
+        match &tree {
+            Sub(v, _) => {
+                assert!(matches!(&v[0], Atom(n, _) if *n == 1.0));
+                assert!(matches!(&v[1], Atom(n, _) if *n == 2.0));
+                assert!(matches!(&v[2], Atom(n, _) if *n == 3.5));
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn map_test() {
+        let tree = Parser::build_parse_tree("(+ 2 3)").unwrap();
+        let doubled = tree.map(&mut |s: String| s.repeat(2));
+        match &doubled {
+            Sub(v, _) => assert!(v[0].is("++")),
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn span_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        let span = tree.span();
+        assert_eq!(span.start, 0);
+        assert_eq!(span.end, TEST_1.len());
+        assert_eq!(span.line, 1);
+        assert_eq!(span.col, 1);
+
+        match &tree {
+            Sub(v, _) => {
+                let plus_span = v[0].span();
+                assert_eq!(&TEST_1[plus_span.start..plus_span.end], "+");
+                assert_eq!(plus_span.line, 1);
+                assert_eq!(plus_span.col, 2);
+
+                let mul_span = v[1].span();
+                assert_eq!(&TEST_1[mul_span.start..mul_span.end], "(* 2 3)");
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn multiline_span_test() {
+        let src = "(+\n  1 2)";
+        let tree = Parser::build_parse_tree(src).unwrap();
+        match &tree {
+            Sub(v, _) => {
+                let one_span = v[1].span();
+                assert_eq!(one_span.line, 2);
+                assert_eq!(one_span.col, 3);
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn string_literal_test() {
+        let tree = Parser::build_parse_tree(r#"("Hello World" foo)"#).unwrap();
+        match &tree {
+            Sub(v, _) => {
+                assert!(v[0].is("Hello World"));
+                assert!(v[1].is("foo"));
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn string_escape_test() {
+        let tree = Parser::build_parse_tree(r#"("a\"b\\c\n\tdA")"#).unwrap();
+        match &tree {
+            Sub(v, _) => assert!(v[0].is("a\"b\\c\n\tdA")),
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn unterminated_string_test() {
+        let result = Parser::build_parse_tree(r#"("unterminated)"#);
+        assert!(matches!(result, Err(ParseError::UnterminatedString {..})));
+    }
+
+    #[test]
+    fn invalid_unicode_escape_test() {
+        let result = Parser::build_parse_tree(r#"("\uD800")"#);
+        assert!(matches!(result, Err(ParseError::InvalidEscape {..})));
+        let result = Parser::build_parse_tree(r#"("\uZZZZ")"#);
+        assert!(matches!(result, Err(ParseError::InvalidEscape {..})));
+    }
+
+    #[test]
+    fn case_preserved_by_default_test() {
+        let tree = Parser::build_parse_tree("(Foo Bar)").unwrap();
+        match &tree {
+            Sub(v, _) => {
+                assert!(v[0].is("Foo"));
+                assert!(v[1].is("Bar"));
+            }
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn display_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        assert_eq!(tree.to_string(), TEST_1);
+    }
+
+    #[test]
+    fn display_quotes_special_atoms_test() {
+        let tree = Parser::build_parse_tree(r#"("Hello World" foo)"#).unwrap();
+        assert_eq!(tree.to_string(), r#"("Hello World" foo)"#);
+    }
+
+    #[test]
+    fn serialize_escapes_quotes_and_backslashes_test() {
+        let src = r#"("a\"b\\c")"#;
+        let tree = Parser::build_parse_tree(src).unwrap();
+        assert_eq!(tree.to_string(), src);
+    }
+
+    #[test]
+    fn write_to_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        let mut buf = Vec::new();
+        tree.write_to(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), TEST_1);
+    }
+
+    #[test]
+    fn pretty_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        assert_eq!(tree.to_pretty(), "(+\n  (*\n    2\n    3)\n  (-\n    5\n    4))");
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let src = r#"(+ "a\"b\\c" foo (- 1 2.5))"#;
+        let tree = Parser::build_parse_tree(src).unwrap();
+        let reparsed = Parser::build_parse_tree(&tree.to_string()).unwrap();
+        assert_eq!(tree, reparsed);
+    }
+
+    #[test]
+    fn round_trip_quotes_comment_prefix_test() {
+        let tree = Parser::build_parse_tree(r#"("a;b" foo)"#).unwrap();
+        let rendered = tree.to_string();
+        assert_eq!(rendered, r#"("a;b" foo)"#);
+        let reparsed = Parser::build_parse_tree(&rendered).unwrap();
+        assert_eq!(tree, reparsed);
+    }
+
+    #[test]
+    fn round_trip_quotes_configured_brackets_test() {
+        let config = ParserConfig::new().with_brackets('[', ']');
+        let tree = Parser::build_parse_tree_with_config(r#"("a]b")"#, &config).unwrap();
+        let rendered = tree.to_string_with_config(&config);
+        assert_eq!(rendered, r#"("a]b")"#);
+        let reparsed = Parser::build_parse_tree_with_config(&rendered, &config).unwrap();
+        assert_eq!(tree, reparsed);
+    }
+
+    #[test]
+    fn bracket_mismatch_test() {
+        let config = ParserConfig::new().with_brackets('[', ']');
+        let result = Parser::build_parse_tree_with_config("(a ]", &config);
+        assert!(matches!(result, Err(ParseError::UnexpectedClose {..})));
+    }
+
+    #[test]
+    fn configurable_brackets_test() {
+        let config = ParserConfig::new().with_brackets('[', ']');
+        let tree = Parser::build_parse_tree_with_config("(a [b c])", &config).unwrap();
+        match &tree {
+            Sub(v, _) => match &v[1] {
+                Sub(inner, _) => {
+                    assert!(inner[0].is("b"));
+                    assert!(inner[1].is("c"));
+                }
+                Atom(..) => assert!(false)
+            },
+            Atom(..) => assert!(false)
+        }
+    }
+
+    #[test]
+    fn line_comment_test() {
+        let tree = Parser::build_parse_tree("(+ 1 2) ; trailing comment\n").unwrap();
+        assert_eq!(tree.to_string(), "(+ 1 2)");
+    }
+
+    #[test]
+    fn custom_comment_prefix_test() {
+        let config = ParserConfig::new().with_comment_prefix('#');
+        let tree = Parser::build_parse_tree_with_config("(+ 1 2) # trailing comment", &config).unwrap();
+        assert_eq!(tree.to_string(), "(+ 1 2)");
+    }
+
+    #[test]
+    fn unmatched_open_test() {
+        let result = Parser::build_parse_tree("(+ 1 2");
+        assert!(matches!(result, Err(ParseError::UnmatchedOpen {..})));
+    }
+
+    #[test]
+    fn cursor_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        let mut cursor = Cursor::new(&tree).unwrap();
+        assert_eq!(cursor.remaining(), 3);
+        assert!(cursor.peek().unwrap().is("+"));
+        cursor.expect_sym("+").unwrap();
+        assert_eq!(cursor.remaining(), 2);
+
+        let mut mul = cursor.expect_sub().unwrap();
+        mul.expect_sym("*").unwrap();
+        assert!(mul.bump().unwrap().is("2"));
+        assert!(mul.bump().unwrap().is("3"));
+        assert!(mul.bump().is_none());
+
+        let mut sub = cursor.expect_sub().unwrap();
+        sub.expect_sym("-").unwrap();
+        assert_eq!(sub.remaining(), 2);
+
+        assert_eq!(cursor.remaining(), 0);
+        assert!(cursor.peek().is_none());
+    }
+
+    #[test]
+    fn cursor_expect_sym_mismatch_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        let mut cursor = Cursor::new(&tree).unwrap();
+        let result = cursor.expect_sym("-");
+        assert!(matches!(result, Err(ParseError::UnexpectedToken {..})));
+    }
+
+    #[test]
+    fn cursor_expect_sub_on_atom_test() {
+        let tree = Parser::build_parse_tree(TEST_1).unwrap();
+        let mut cursor = Cursor::new(&tree).unwrap();
+        let result = cursor.expect_sub();
+        assert!(matches!(result, Err(ParseError::UnexpectedToken {..})));
+    }
+
+    #[test]
+    fn cursor_none_for_atom_test() {
+        let tree = Parser::build_parse_tree("42").unwrap();
+        assert!(Cursor::new(&tree).is_none());
     }
 }